@@ -0,0 +1,437 @@
+//! Minimal ELF64 parsing used to walk the dynamic dependency graph of a
+//! binary without shelling out to `ldd`.
+//!
+//! This only understands the handful of fields we actually need: the
+//! `PT_INTERP` program header (the dynamic loader path) and the
+//! `PT_DYNAMIC` segment (`DT_NEEDED` SONAMEs plus `DT_RPATH`/`DT_RUNPATH`
+//! search directories). It deliberately does not depend on any crates, as
+//! none are vendored for this tool.
+
+use std::path::{Path, PathBuf};
+
+/// Errors that can occur while parsing an ELF file
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to read the file off of disk
+    Read(std::io::Error),
+
+    /// File was too short to contain a valid ELF header
+    TooShort,
+
+    /// File didn't start with the ELF magic bytes
+    BadMagic,
+
+    /// We only support 64-bit, little-endian ELFs (matches the rest of
+    /// this tool, which is x86-64 only)
+    UnsupportedClass,
+
+    /// A segment referenced a range outside of the file
+    OutOfBounds,
+
+    /// A string table entry was not NUL-terminated
+    UnterminatedString,
+
+    /// `.dynamic` referenced a string (a `DT_NEEDED` SONAME, `DT_RPATH` or
+    /// `DT_RUNPATH`) but never declared a `DT_STRTAB` to resolve it
+    /// against, which would otherwise silently drop the dependency
+    NoStringTable,
+}
+
+/// Program header type for an interpreter request (`PT_INTERP`)
+const PT_INTERP: u32 = 3;
+
+/// Program header type for a loadable segment (`PT_LOAD`)
+const PT_LOAD: u32 = 1;
+
+/// Program header type for dynamic linking information (`PT_DYNAMIC`)
+const PT_DYNAMIC: u32 = 2;
+
+/// Dynamic tag marking the end of the `.dynamic` array
+const DT_NULL: i64 = 0;
+
+/// Dynamic tag for a needed shared object SONAME
+const DT_NEEDED: i64 = 1;
+
+/// Dynamic tag for the address of the dynamic string table
+const DT_STRTAB: i64 = 5;
+
+/// Dynamic tag for `RPATH` (offset into the string table)
+const DT_RPATH: i64 = 15;
+
+/// Dynamic tag for `RUNPATH` (offset into the string table)
+const DT_RUNPATH: i64 = 29;
+
+/// Dependency information extracted from an ELF file's dynamic section
+#[derive(Debug, Default)]
+pub struct DynInfo {
+    /// Path to the dynamic loader requested by `PT_INTERP`, if any
+    pub interp: Option<String>,
+
+    /// `DT_NEEDED` SONAMEs, in the order they appear in `.dynamic`
+    pub needed: Vec<String>,
+
+    /// Search directories from `DT_RUNPATH`, falling back to `DT_RPATH`
+    /// if no `DT_RUNPATH` was present, with `$ORIGIN` already resolved
+    /// to the directory containing the parsed file
+    pub search_dirs: Vec<PathBuf>,
+}
+
+/// Read a `u16` out of `buf` at `off`, little-endian
+fn u16_at(buf: &[u8], off: usize) -> Result<u16, Error> {
+    let end = off.checked_add(2).ok_or(Error::OutOfBounds)?;
+    let bytes: [u8; 2] = buf.get(off..end)
+        .ok_or(Error::OutOfBounds)?
+        .try_into().unwrap();
+    Ok(u16::from_le_bytes(bytes))
+}
+
+/// Read a `u32` out of `buf` at `off`, little-endian
+fn u32_at(buf: &[u8], off: usize) -> Result<u32, Error> {
+    let end = off.checked_add(4).ok_or(Error::OutOfBounds)?;
+    let bytes: [u8; 4] = buf.get(off..end)
+        .ok_or(Error::OutOfBounds)?
+        .try_into().unwrap();
+    Ok(u32::from_le_bytes(bytes))
+}
+
+/// Read a `u64` out of `buf` at `off`, little-endian
+fn u64_at(buf: &[u8], off: usize) -> Result<u64, Error> {
+    let end = off.checked_add(8).ok_or(Error::OutOfBounds)?;
+    let bytes: [u8; 8] = buf.get(off..end)
+        .ok_or(Error::OutOfBounds)?
+        .try_into().unwrap();
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Read a `i64` out of `buf` at `off`, little-endian
+fn i64_at(buf: &[u8], off: usize) -> Result<i64, Error> {
+    u64_at(buf, off).map(|x| x as i64)
+}
+
+/// Add two offsets derived from the file, erroring out instead of
+/// panicking (debug builds) or silently wrapping to the wrong offset
+/// (release builds) on a malformed/corrupt file whose fields overflow
+fn add(a: usize, b: usize) -> Result<usize, Error> {
+    a.checked_add(b).ok_or(Error::OutOfBounds)
+}
+
+/// Multiply two offsets derived from the file, with the same overflow
+/// handling as [`add`]
+fn mul(a: usize, b: usize) -> Result<usize, Error> {
+    a.checked_mul(b).ok_or(Error::OutOfBounds)
+}
+
+/// Read a NUL-terminated string starting at `off` in `buf`
+fn cstr_at(buf: &[u8], off: usize) -> Result<String, Error> {
+    let slice = buf.get(off..).ok_or(Error::OutOfBounds)?;
+    let end = slice.iter().position(|&b| b == 0)
+        .ok_or(Error::UnterminatedString)?;
+    Ok(String::from_utf8_lossy(&slice[..end]).into_owned())
+}
+
+/// One parsed `Elf64_Phdr`
+struct ProgramHeader {
+    p_type:   u32,
+    p_offset: u64,
+    p_vaddr:  u64,
+    p_filesz: u64,
+}
+
+/// Translate a virtual address into a file offset by finding the
+/// `PT_LOAD` segment that covers it
+fn vaddr_to_offset(phdrs: &[ProgramHeader], vaddr: u64) -> Result<u64, Error> {
+    for ph in phdrs {
+        let end = match ph.p_vaddr.checked_add(ph.p_filesz) {
+            Some(end) => end,
+            None => continue,
+        };
+        if ph.p_type == PT_LOAD && vaddr >= ph.p_vaddr && vaddr < end {
+            return ph.p_offset.checked_add(vaddr - ph.p_vaddr)
+                .ok_or(Error::OutOfBounds);
+        }
+    }
+    Err(Error::OutOfBounds)
+}
+
+/// Parse the ELF file at `path` and extract its `PT_INTERP` and
+/// `PT_DYNAMIC` contents
+pub fn parse(path: &Path) -> Result<DynInfo, Error> {
+    let buf = std::fs::read(path).map_err(Error::Read)?;
+
+    if buf.len() < 64 {
+        return Err(Error::TooShort);
+    }
+    if &buf[0..4] != b"\x7fELF" {
+        return Err(Error::BadMagic);
+    }
+    // EI_CLASS (4) must be ELFCLASS64, EI_DATA (5) must be ELFDATA2LSB
+    if buf[4] != 2 || buf[5] != 1 {
+        return Err(Error::UnsupportedClass);
+    }
+
+    let e_phoff     = u64_at(&buf, 32)?;
+    let e_phentsize = u16_at(&buf, 54)? as usize;
+    let e_phnum     = u16_at(&buf, 56)? as usize;
+
+    // Parse every program header
+    let mut phdrs = Vec::with_capacity(e_phnum);
+    for idx in 0..e_phnum {
+        let base = add(e_phoff as usize, mul(idx, e_phentsize)?)?;
+        phdrs.push(ProgramHeader {
+            p_type:   u32_at(&buf, base)?,
+            p_offset: u64_at(&buf, add(base, 8)?)?,
+            p_vaddr:  u64_at(&buf, add(base, 16)?)?,
+            p_filesz: u64_at(&buf, add(base, 32)?)?,
+        });
+    }
+
+    let mut info = DynInfo::default();
+
+    // `PT_INTERP` holds the loader path as a raw (non-NUL-required, but in
+    // practice NUL-terminated) string in the file
+    if let Some(ph) = phdrs.iter().find(|x| x.p_type == PT_INTERP) {
+        let start = ph.p_offset as usize;
+        let end = add(start, ph.p_filesz as usize)?;
+        let raw = buf.get(start..end).ok_or(Error::OutOfBounds)?;
+        let len = raw.iter().position(|&b| b == 0).unwrap_or(raw.len());
+        info.interp = Some(String::from_utf8_lossy(&raw[..len]).into_owned());
+    }
+
+    // `PT_DYNAMIC` holds the `.dynamic` array. Its string references
+    // (SONAME, RPATH, RUNPATH) are offsets into the string table pointed
+    // to by the `DT_STRTAB` entry, which is itself a virtual address we
+    // need to translate via the `PT_LOAD` segments
+    if let Some(ph) = phdrs.iter().find(|x| x.p_type == PT_DYNAMIC) {
+        let base = ph.p_offset as usize;
+        let count = ph.p_filesz as usize / 16;
+
+        // First pass: find `DT_STRTAB`
+        let mut strtab_vaddr = None;
+        for idx in 0..count {
+            let entry = add(base, mul(idx, 16)?)?;
+            let tag = i64_at(&buf, entry)?;
+            if tag == DT_NULL {
+                break;
+            }
+            if tag == DT_STRTAB {
+                strtab_vaddr = Some(u64_at(&buf, add(entry, 8)?)?);
+            }
+        }
+        let strtab_off = strtab_vaddr
+            .map(|v| vaddr_to_offset(&phdrs, v))
+            .transpose()?;
+
+        // Second pass: pull out `DT_NEEDED`/`DT_RPATH`/`DT_RUNPATH`
+        let mut rpath = None;
+        let mut runpath = None;
+        for idx in 0..count {
+            let entry = add(base, mul(idx, 16)?)?;
+            let tag = i64_at(&buf, entry)?;
+            if tag == DT_NULL {
+                break;
+            }
+            let val = u64_at(&buf, add(entry, 8)?)?;
+
+            if !matches!(tag, DT_NEEDED | DT_RPATH | DT_RUNPATH) {
+                continue;
+            }
+
+            // Every one of these tags is a string table offset, so there
+            // had better be a string table; silently ignoring them here
+            // would mean dependencies go missing with no error at all
+            let strtab_off = strtab_off.ok_or(Error::NoStringTable)?;
+            let str_off = add(strtab_off as usize, val as usize)?;
+
+            match tag {
+                DT_NEEDED => {
+                    info.needed.push(cstr_at(&buf, str_off)?);
+                }
+                DT_RPATH => {
+                    rpath = Some(cstr_at(&buf, str_off)?);
+                }
+                DT_RUNPATH => {
+                    runpath = Some(cstr_at(&buf, str_off)?);
+                }
+                _ => unreachable!(),
+            }
+        }
+
+        // Glibc prefers `DT_RUNPATH` over `DT_RPATH` when both are present
+        let origin_dir = path.parent().unwrap_or_else(|| Path::new("."));
+        if let Some(path_list) = runpath.or(rpath) {
+            for dir in path_list.split(':').filter(|x| !x.is_empty()) {
+                info.search_dirs.push(resolve_origin(dir, origin_dir));
+            }
+        }
+    }
+
+    Ok(info)
+}
+
+/// Resolve a `$ORIGIN` (or `${ORIGIN}`) token in an `RPATH`/`RUNPATH`
+/// entry to the directory containing the binary that referenced it
+fn resolve_origin(dir: &str, origin_dir: &Path) -> PathBuf {
+    let replaced = dir
+        .replace("$ORIGIN", &origin_dir.to_string_lossy())
+        .replace("${ORIGIN}", &origin_dir.to_string_lossy());
+    PathBuf::from(replaced)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a minimal, well-formed ELF64 LE file with a `PT_INTERP` of
+    /// `interp`, a single `PT_LOAD` covering the whole file (so every
+    /// virtual address equals its file offset), and a `PT_DYNAMIC`
+    /// segment with a `DT_NEEDED` per entry of `needed`, a `DT_RUNPATH`
+    /// if `runpath` is given, and a `DT_STRTAB` (pass `include_strtab:
+    /// false` to omit both `DT_STRTAB` and the string table itself)
+    fn build_elf(interp: &str, needed: &[&str], runpath: Option<&str>,
+            include_strtab: bool) -> Vec<u8> {
+        fn push_str(buf: &mut Vec<u8>, s: &str) -> u64 {
+            let off = buf.len() as u64;
+            buf.extend_from_slice(s.as_bytes());
+            buf.push(0);
+            off
+        }
+        fn push_dyn(buf: &mut Vec<u8>, tag: i64, val: u64) {
+            buf.extend_from_slice(&tag.to_le_bytes());
+            buf.extend_from_slice(&val.to_le_bytes());
+        }
+        fn push_phdr(buf: &mut Vec<u8>, p_type: u32, p_offset: u64,
+                p_vaddr: u64, p_filesz: u64) {
+            buf.extend_from_slice(&p_type.to_le_bytes());
+            buf.extend_from_slice(&0u32.to_le_bytes()); // p_flags
+            buf.extend_from_slice(&p_offset.to_le_bytes());
+            buf.extend_from_slice(&p_vaddr.to_le_bytes());
+            buf.extend_from_slice(&p_vaddr.to_le_bytes()); // p_paddr
+            buf.extend_from_slice(&p_filesz.to_le_bytes());
+            buf.extend_from_slice(&p_filesz.to_le_bytes()); // p_memsz
+            buf.extend_from_slice(&0u64.to_le_bytes()); // p_align
+        }
+
+        let mut strtab = vec![0u8]; // index 0 is conventionally empty
+        let needed_offs: Vec<u64> = needed.iter()
+            .map(|s| push_str(&mut strtab, s)).collect();
+        let runpath_off = runpath.map(|s| push_str(&mut strtab, s));
+
+        let interp_off = 64 + 3 * 56; // right after the 3 program headers
+        let interp_bytes = {
+            let mut v = interp.as_bytes().to_vec();
+            v.push(0);
+            v
+        };
+        let dynamic_off = interp_off + interp_bytes.len() as u64;
+
+        let mut dynamic = Vec::new();
+        for off in &needed_offs {
+            push_dyn(&mut dynamic, DT_NEEDED, *off);
+        }
+        if let Some(off) = runpath_off {
+            push_dyn(&mut dynamic, DT_RUNPATH, off);
+        }
+        // Account for the DT_STRTAB entry (if any) and the DT_NULL
+        // terminator that both still need to be appended after this
+        let strtab_off = dynamic_off + dynamic.len() as u64
+            + if include_strtab { 16 } else { 0 } + 16;
+        if include_strtab {
+            push_dyn(&mut dynamic, DT_STRTAB, strtab_off);
+        }
+        push_dyn(&mut dynamic, DT_NULL, 0);
+
+        let total_len = strtab_off + strtab.len() as u64;
+
+        let mut buf = vec![0u8; 64];
+        buf[0..4].copy_from_slice(b"\x7fELF");
+        buf[4] = 2; // ELFCLASS64
+        buf[5] = 1; // ELFDATA2LSB
+        buf[32..40].copy_from_slice(&64u64.to_le_bytes()); // e_phoff
+        buf[54..56].copy_from_slice(&56u16.to_le_bytes()); // e_phentsize
+        buf[56..58].copy_from_slice(&3u16.to_le_bytes());  // e_phnum
+
+        push_phdr(&mut buf, PT_INTERP, interp_off, 0, interp_bytes.len() as u64);
+        push_phdr(&mut buf, PT_LOAD, 0, 0, total_len);
+        push_phdr(&mut buf, PT_DYNAMIC, dynamic_off, dynamic_off,
+            dynamic.len() as u64);
+
+        assert_eq!(buf.len() as u64, interp_off);
+        buf.extend_from_slice(&interp_bytes);
+        assert_eq!(buf.len() as u64, dynamic_off);
+        buf.extend_from_slice(&dynamic);
+        assert_eq!(buf.len() as u64, strtab_off);
+        if include_strtab {
+            buf.extend_from_slice(&strtab);
+        }
+
+        buf
+    }
+
+    /// Write `contents` to a fresh file under the OS temp dir, named to
+    /// avoid colliding with other tests running in parallel
+    fn write_temp(name: &str, contents: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir()
+            .join(format!("qemu_chrooter-test-{name}-{}", std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn parses_interp_needed_and_runpath() {
+        let buf = build_elf("/lib/ld-linux-x86-64.so.2",
+            &["libfoo.so.1", "libbar.so.2"],
+            Some("$ORIGIN/../lib:/opt/lib"), true);
+        let path = write_temp("parses-interp-needed-and-runpath", &buf);
+
+        let info = parse(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(info.interp.as_deref(), Some("/lib/ld-linux-x86-64.so.2"));
+        assert_eq!(info.needed, vec!["libfoo.so.1", "libbar.so.2"]);
+        assert_eq!(info.search_dirs, vec![
+            path.parent().unwrap().join("../lib"),
+            PathBuf::from("/opt/lib"),
+        ]);
+    }
+
+    #[test]
+    fn missing_strtab_with_needed_entries_is_an_error() {
+        let buf = build_elf("/lib/ld-linux-x86-64.so.2", &["libfoo.so.1"],
+            None, false);
+        let path = write_temp("missing-strtab-is-an-error", &buf);
+
+        let result = parse(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(result, Err(Error::NoStringTable)));
+    }
+
+    #[test]
+    fn huge_phoff_errors_instead_of_overflowing() {
+        // A minimal 64-byte header claiming a `e_phoff` so close to
+        // `u64::MAX` that reading the very first program header's fields
+        // would overflow a `usize` offset
+        let mut buf = vec![0u8; 64];
+        buf[0..4].copy_from_slice(b"\x7fELF");
+        buf[4] = 2; // ELFCLASS64
+        buf[5] = 1; // ELFDATA2LSB
+        buf[32..40].copy_from_slice(&(u64::MAX - 2).to_le_bytes()); // e_phoff
+        buf[54..56].copy_from_slice(&56u16.to_le_bytes()); // e_phentsize
+        buf[56..58].copy_from_slice(&1u16.to_le_bytes());  // e_phnum
+        let path = write_temp("huge-phoff-errors-instead-of-overflowing", &buf);
+
+        let result = parse(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(result, Err(Error::OutOfBounds)));
+    }
+
+    #[test]
+    fn resolve_origin_substitutes_both_forms() {
+        let origin_dir = Path::new("/opt/qemu/bin");
+        assert_eq!(resolve_origin("$ORIGIN/../lib", origin_dir),
+            PathBuf::from("/opt/qemu/bin/../lib"));
+        assert_eq!(resolve_origin("${ORIGIN}/../lib", origin_dir),
+            PathBuf::from("/opt/qemu/bin/../lib"));
+    }
+}
@@ -0,0 +1,319 @@
+//! Path layout shared between the file copier and the wrapper/binfmt_misc
+//! generation, plus the wrapper script and binfmt_misc registration logic
+//! itself.
+//!
+//! Keeping the destination paths in one place means the copier and the
+//! generated wrapper can never disagree about where something landed in
+//! the chroot.
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// Errors specific to wrapper/binfmt_misc generation
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to write the wrapper script into the chroot
+    WriteWrapper(PathBuf, std::io::Error),
+
+    /// Failed to mark the wrapper script executable
+    SetWrapperPermissions(PathBuf, std::io::Error),
+
+    /// Couldn't figure out which architecture the QEMU binary emulates
+    /// from its filename (expected e.g. `qemu-arm`)
+    UnrecognizedQemuName(PathBuf),
+
+    /// The architecture parsed out of the QEMU binary's filename isn't one
+    /// we know the binfmt_misc magic/mask for
+    UnsupportedBinfmtArch(String),
+
+    /// Failed to write the registration line to
+    /// `/proc/sys/fs/binfmt_misc/register`
+    BinfmtRegister(std::io::Error),
+
+    /// The dynamic loader's path (from `PT_INTERP`) has no parent
+    /// directory and/or no file name component to place it at in the
+    /// chroot, e.g. `/`. Almost always a sign of a crafted or corrupt
+    /// binary rather than a real loader.
+    InvalidLoaderPath(PathBuf),
+}
+
+/// Destinations inside (and relative to) the chroot, factored out of the
+/// copy loop so the wrapper generator agrees with it on where everything
+/// ends up
+pub struct Layout {
+    /// Directory dependency libraries are copied into, e.g. `lib64/x86_64`
+    pub lib_dir: PathBuf,
+
+    /// Directory the QEMU binary itself is copied into, e.g. `usr/bin`
+    pub bin_dir: PathBuf,
+
+    /// Directory the dynamic loader is copied into. This has to match the
+    /// loader's real absolute path, since that's the path baked into every
+    /// binary's `PT_INTERP`
+    pub loader_dir: PathBuf,
+
+    /// File name of the QEMU binary
+    pub qemu_name: PathBuf,
+
+    /// File name of the dynamic loader
+    pub loader_name: PathBuf,
+}
+
+impl Layout {
+    /// Compute the chroot layout for a given QEMU binary and loader.
+    ///
+    /// Fails with [`Error::InvalidLoaderPath`] if `loader` is too
+    /// degenerate to place in the chroot (no parent directory, not an
+    /// absolute path, or no file name) — a crafted or corrupt
+    /// `PT_INTERP` such as `/` can reach here without tripping the ELF
+    /// parser, since `/` is itself a perfectly valid (if useless) path.
+    pub fn new(qemu: &Path, loader: &Path) -> Result<Self, Error> {
+        let loader_parent = loader.parent()
+            .ok_or_else(|| Error::InvalidLoaderPath(loader.to_path_buf()))?;
+        let loader_dir = loader_parent.strip_prefix("/")
+            .map_err(|_| Error::InvalidLoaderPath(loader.to_path_buf()))?
+            .to_path_buf();
+        let loader_name = loader.file_name()
+            .ok_or_else(|| Error::InvalidLoaderPath(loader.to_path_buf()))?;
+
+        Ok(Layout {
+            lib_dir: PathBuf::from("lib64/x86_64"),
+            bin_dir: PathBuf::from("usr/bin"),
+            loader_dir,
+            qemu_name: PathBuf::from(qemu.file_name().unwrap()),
+            loader_name: PathBuf::from(loader_name),
+        })
+    }
+
+    /// Path to the copied QEMU binary, relative to the chroot
+    pub fn qemu_in_chroot(&self) -> PathBuf {
+        self.bin_dir.join(&self.qemu_name)
+    }
+
+    /// Path to the copied loader, relative to the chroot
+    pub fn loader_in_chroot(&self) -> PathBuf {
+        self.loader_dir.join(&self.loader_name)
+    }
+
+    /// Absolute path the copied QEMU binary will have from *inside* the
+    /// chroot
+    pub fn qemu_absolute(&self) -> PathBuf {
+        Path::new("/").join(self.qemu_in_chroot())
+    }
+
+    /// Absolute path the copied loader will have from *inside* the chroot
+    pub fn loader_absolute(&self) -> PathBuf {
+        Path::new("/").join(self.loader_in_chroot())
+    }
+
+    /// Absolute path the library directory will have from *inside* the
+    /// chroot
+    pub fn lib_dir_absolute(&self) -> PathBuf {
+        Path::new("/").join(&self.lib_dir)
+    }
+}
+
+/// Where the generated wrapper script is placed in the chroot
+pub fn wrapper_path() -> PathBuf {
+    PathBuf::from("usr/bin/qemu-run")
+}
+
+/// Generate the contents of the `qemu-run` wrapper script, which invokes
+/// the copied QEMU through the correct loader with the right library
+/// search path, so that a foreign-arch binary can be run without the
+/// caller knowing anything about the chroot's layout
+fn wrapper_script(layout: &Layout) -> String {
+    format!(
+        "#!/bin/sh\n\
+         # Generated by qemu_chrooter. Runs the emulator with the loader\n\
+         # and library search path it needs to find inside this chroot.\n\
+         exec {loader} --library-path {lib_dir} {qemu} \"$@\"\n",
+        loader = layout.loader_absolute().display(),
+        lib_dir = layout.lib_dir_absolute().display(),
+        qemu = layout.qemu_absolute().display(),
+    )
+}
+
+/// Write the `qemu-run` wrapper script into `chroot`
+pub fn write_wrapper(chroot: &Path, layout: &Layout) -> Result<(), Error> {
+    let dest = chroot.join(wrapper_path());
+
+    std::fs::write(&dest, wrapper_script(layout))
+        .map_err(|x| Error::WriteWrapper(dest.clone(), x))?;
+
+    let mut perms = std::fs::metadata(&dest)
+        .map_err(|x| Error::SetWrapperPermissions(dest.clone(), x))?
+        .permissions();
+    perms.set_mode(0o755);
+    std::fs::set_permissions(&dest, perms)
+        .map_err(|x| Error::SetWrapperPermissions(dest, x))?;
+
+    Ok(())
+}
+
+/// `(EI_CLASS, EI_DATA, e_machine)` for an architecture binfmt_misc needs
+/// to recognize, keyed by the architecture name as it appears in QEMU's
+/// own binary name (`qemu-<arch>`)
+const BINFMT_ARCHES: &[(&str, u8, u8, u16)] = &[
+    // name        class  data  e_machine
+    ("arm",        1,     1,    40),  // EM_ARM, 32-bit LE
+    ("armeb",      1,     2,    40),  // EM_ARM, 32-bit BE
+    ("aarch64",    2,     1,    183), // EM_AARCH64, 64-bit LE
+    ("aarch64_be", 2,     2,    183), // EM_AARCH64, 64-bit BE
+    ("mips",       1,     2,    8),   // EM_MIPS, 32-bit BE
+    ("mipsel",     1,     1,    8),   // EM_MIPS, 32-bit LE
+    ("mips64",     2,     2,    8),   // EM_MIPS, 64-bit BE
+    ("mips64el",   2,     1,    8),   // EM_MIPS, 64-bit LE
+    ("ppc",        1,     2,    20),  // EM_PPC, 32-bit BE
+    ("ppc64",      2,     2,    21),  // EM_PPC64, 64-bit BE
+    ("ppc64le",    2,     1,    21),  // EM_PPC64, 64-bit LE
+    ("sparc64",    2,     2,    43),  // EM_SPARCV9, 64-bit BE
+    ("s390x",      2,     2,    22),  // EM_S390, 64-bit BE
+    ("riscv32",    1,     1,    243), // EM_RISCV, 32-bit LE
+    ("riscv64",    2,     1,    243), // EM_RISCV, 64-bit LE
+    ("sh4",        1,     1,    42),  // EM_SH, 32-bit LE
+    ("sh4eb",      1,     2,    42),  // EM_SH, 32-bit BE
+    ("microblaze", 1,     2,    189), // EM_MICROBLAZE, 32-bit BE
+    ("m68k",       1,     2,    4),   // EM_68K, 32-bit BE
+];
+
+/// Parse the target architecture out of a QEMU binary's filename, which
+/// is expected to follow the usual `qemu-<arch>` naming convention (e.g.
+/// `qemu-arm`, `qemu-aarch64`)
+fn arch_from_qemu_name(qemu: &Path) -> Result<String, Error> {
+    qemu.file_name()
+        .and_then(|x| x.to_str())
+        .and_then(|x| x.strip_prefix("qemu-"))
+        .map(str::to_owned)
+        .ok_or_else(|| Error::UnrecognizedQemuName(qemu.to_path_buf()))
+}
+
+/// Build the `(magic, mask)` byte strings binfmt_misc needs to recognize
+/// ELF binaries built for `(class, data, machine)`.
+///
+/// The OS/ABI, ABI version and padding bytes (`e_ident[7..16]`) are
+/// masked out since they vary across toolchains, and the low bit of
+/// `e_type` (along with its whole high byte) is masked out so both
+/// `ET_EXEC` and `ET_DYN` (static vs. PIE) binaries match.
+fn elf_magic_mask(class: u8, data: u8, machine: u16) -> (Vec<u8>, Vec<u8>) {
+    let mut magic = vec![0x7f, b'E', b'L', b'F', class, data, 1, 0,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    let mut mask = vec![0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff];
+
+    // e_type (offset 16..18): ET_EXEC == 2, mask out the bit that
+    // distinguishes it from ET_DYN == 3
+    let (type_lo, type_hi) = if data == 1 { (16, 17) } else { (17, 16) };
+    magic[type_lo] = 2;
+    mask[type_lo] = 0xfe;
+    magic[type_hi] = 0;
+    mask[type_hi] = 0x00;
+
+    // e_machine (offset 18..20), byte order depends on target endianness
+    let (machine_lo, machine_hi) = if data == 1 { (18, 19) } else { (19, 18) };
+    magic[machine_lo] = (machine & 0xff) as u8;
+    magic[machine_hi] = (machine >> 8) as u8;
+
+    (magic, mask)
+}
+
+/// Format a byte string the way `/proc/sys/fs/binfmt_misc/register`
+/// expects: each byte as `\xXX`
+fn format_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("\\x{b:02x}")).collect()
+}
+
+/// Register `wrapper_host_path` (the absolute, host-visible path to the
+/// wrapper script, e.g. `<chroot>/usr/bin/qemu-run`) with binfmt_misc so
+/// that any binary matching `qemu`'s target architecture transparently
+/// runs through it, even after a `chroot()` (the `F` flag has the kernel
+/// open the interpreter immediately, before the calling process changes
+/// root).
+pub fn register_binfmt(qemu: &Path, wrapper_host_path: &Path)
+        -> Result<(), Error> {
+    let arch = arch_from_qemu_name(qemu)?;
+    let (_, class, data, machine) = *BINFMT_ARCHES.iter()
+        .find(|(name, ..)| *name == arch)
+        .ok_or_else(|| Error::UnsupportedBinfmtArch(arch.clone()))?;
+
+    let (magic, mask) = elf_magic_mask(class, data, machine);
+
+    let line = format!(":qemu_chrooter-{arch}:M::{magic}:{mask}:{interp}:F\n",
+        magic = format_bytes(&magic),
+        mask = format_bytes(&mask),
+        interp = wrapper_host_path.display());
+
+    std::fs::write("/proc/sys/fs/binfmt_misc/register", line)
+        .map_err(Error::BinfmtRegister)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn elf_magic_mask_aarch64_le() {
+        let (magic, mask) = elf_magic_mask(2, 1, 183);
+        assert_eq!(magic, vec![
+            0x7f, b'E', b'L', b'F', 2, 1, 1, 0,
+            0, 0, 0, 0, 0, 0, 0, 0,
+            0x02, 0x00, 0xb7, 0x00,
+        ]);
+        assert_eq!(mask, vec![
+            0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0xfe, 0x00, 0xff, 0xff,
+        ]);
+    }
+
+    #[test]
+    fn elf_magic_mask_big_endian_swaps_type_and_machine_bytes() {
+        // aarch64_be: same class/machine as aarch64, but EI_DATA == 2
+        let (magic, _mask) = elf_magic_mask(2, 2, 183);
+        // e_type and e_machine bytes are swapped relative to the LE case
+        assert_eq!(&magic[16..20], &[0x00, 0x02, 0x00, 0xb7]);
+    }
+
+    #[test]
+    fn elf_magic_mask_covers_every_binfmt_arch() {
+        // Every entry in BINFMT_ARCHES should produce a 20-byte magic/mask
+        // pair without panicking, regardless of class/data/machine
+        for (name, class, data, machine) in BINFMT_ARCHES {
+            let (magic, mask) = elf_magic_mask(*class, *data, *machine);
+            assert_eq!(magic.len(), 20, "magic length wrong for {name}");
+            assert_eq!(mask.len(), 20, "mask length wrong for {name}");
+        }
+    }
+
+    #[test]
+    fn format_bytes_matches_binfmt_misc_syntax() {
+        assert_eq!(format_bytes(&[0x7f, 0x00, 0xb7]), "\\x7f\\x00\\xb7");
+    }
+
+    #[test]
+    fn arch_from_qemu_name_strips_prefix() {
+        assert_eq!(arch_from_qemu_name(Path::new("/usr/bin/qemu-aarch64"))
+            .unwrap(), "aarch64");
+        assert!(arch_from_qemu_name(Path::new("/usr/bin/not-qemu")).is_err());
+    }
+
+    #[test]
+    fn layout_new_rejects_degenerate_loader_path() {
+        // A crafted/corrupt `PT_INTERP` of `/` parses as a perfectly
+        // valid path (it exists, it's not a symlink), but has no parent
+        // directory to place it at inside the chroot
+        assert!(matches!(
+            Layout::new(Path::new("/usr/bin/qemu-aarch64"), Path::new("/")),
+            Err(Error::InvalidLoaderPath(_))
+        ));
+    }
+
+    #[test]
+    fn layout_new_accepts_a_normal_loader_path() {
+        let layout = Layout::new(Path::new("/usr/bin/qemu-aarch64"),
+            Path::new("/lib/ld-linux-aarch64.so.1")).unwrap();
+        assert_eq!(layout.loader_dir, PathBuf::from("lib"));
+        assert_eq!(layout.loader_name,
+            PathBuf::from("ld-linux-aarch64.so.1"));
+    }
+}
@@ -1,18 +1,33 @@
-//! Small tool to move QEMU and all of it's dynamic libraries into a chroot
-//! for x86-64 Linux targets
+//! Small tool to move one or more QEMU binaries (or a whole directory of
+//! them), plus all of their dynamic libraries, into a chroot for x86-64
+//! Linux targets
 //!
-//! This tool will use `ldd` to determine the runtime dependencies of QEMU and
-//! copy QEMU and all of these dependencies into the specified chroot
-//! environment. This assists in using dynamically built QEMU inside of a
-//! different architecture's chroot.
+//! By default this tool parses each binary's own ELF headers to determine
+//! its runtime dependencies, without executing anything from the target
+//! binary; shelling out to `ldd` instead is available behind
+//! `--backend=ldd`. Either way, every dependency is copied into the chroot,
+//! reproducing the SONAME symlink chain used to reach it (e.g. `libfoo.so.6
+//! -> libfoo.so.6.2.1`) rather than flattening it down to one file. This
+//! assists in using dynamically built QEMU inside of a different
+//! architecture's chroot.
+//!
+//! QEMU's own `dlopen()`'d modules (block/accel/audio/net backends) are
+//! discovered and copied the same way, and a single-binary install gets a
+//! generated `qemu-run` wrapper script plus, with `--register-binfmt`,
+//! registration in `binfmt_misc` so foreign-arch binaries transparently run
+//! through it.
 //!
 //! This is designed to conflict with existing binaries in the chroot minimally
 //! by installing the dependencies into `/lib64/x86_64`, which is specific to
 //! x86-64 programs.
 
+use std::collections::HashSet;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
+mod elf;
+mod launch;
+
 /// Wrapper type around [`Error`]
 type Result<T> = std::result::Result<T, Error>;
 
@@ -40,6 +55,17 @@ enum Error {
     /// Our very low-quality parser for `ldd` output failed
     UnexpectedLddOutput,
 
+    /// Failed to parse a binary's ELF headers with the `elf` backend
+    ElfParse(PathBuf, elf::Error),
+
+    /// Neither backend found a dynamic loader (`PT_INTERP`) for a binary,
+    /// which almost always means it's statically linked and doesn't need
+    /// one in the first place
+    NoLoaderFound(PathBuf),
+
+    /// A `DT_NEEDED` SONAME couldn't be found in any search directory
+    LibraryNotFound(String),
+
     /// Failed to canonicalize a library path
     LibCanonicalize(std::io::Error),
 
@@ -49,34 +75,193 @@ enum Error {
 
     /// Failed to copy dependency into chroot
     CopyFile(PathBuf, PathBuf, std::io::Error),
+
+    /// Failed to list the contents of QEMU's module directory
+    ReadModulesDir(PathBuf, std::io::Error),
+
+    /// Failed to generate the launcher wrapper or register it with
+    /// binfmt_misc
+    Launch(launch::Error),
+
+    /// Following a chain of symlinks didn't reach a real file within 32
+    /// hops, which is almost certainly a symlink loop
+    TooManySymlinks(PathBuf),
+
+    /// Failed to create a symlink reproducing part of a library's SONAME
+    /// link chain
+    CreateSymlink(PathBuf, PathBuf, std::io::Error),
 }
 
-/// Entry point
-fn main() -> Result<()> {
-    // Get the arguments
-    let args: Vec<String> = std::env::args().collect::<Vec<_>>();
-    if args.len() != 3 {
-        println!("usage: qemu_chrooter <path to QEMU binary> \
-            <path to chroot>");
-        return Err(Error::InvalidArgs);
+/// A path together with every symlink that was followed to reach it:
+/// `chain[0]` is the path as it was originally referenced (e.g. the
+/// SONAME a `DT_NEEDED` entry asked for), `chain.last()` is the real,
+/// non-symlink file.
+type SymlinkChain = Vec<PathBuf>;
+
+/// Follow `start` through however many symlinks it takes to reach a real
+/// file, returning every path visited along the way (`start` included).
+/// This lets us reproduce the exact link layout a real `/lib64` has
+/// (e.g. `libfoo.so.6 -> libfoo.so.6.2.1`) instead of flattening
+/// everything down to the final target's name.
+fn resolve_symlink_chain(start: &Path) -> Result<SymlinkChain> {
+    let mut chain = vec![start.to_path_buf()];
+    let mut current = start.to_path_buf();
+
+    for _ in 0..32 {
+        let meta = std::fs::symlink_metadata(&current)
+            .map_err(Error::LibCanonicalize)?;
+        if !meta.file_type().is_symlink() {
+            // Nothing left to follow; canonicalize just to normalize the
+            // path (e.g. collapse any `..` components). `start` itself is
+            // kept as referenced in `chain[0]`
+            let real = current.canonicalize().map_err(Error::LibCanonicalize)?;
+            if chain.len() == 1 {
+                chain.push(real);
+            } else {
+                *chain.last_mut().unwrap() = real;
+            }
+            return Ok(chain);
+        }
+
+        let target = std::fs::read_link(&current).map_err(Error::LibCanonicalize)?;
+        current = if target.is_absolute() {
+            target
+        } else {
+            current.parent().unwrap().join(target)
+        };
+        chain.push(current.clone());
+    }
+
+    Err(Error::TooManySymlinks(start.to_path_buf()))
+}
+
+/// Which strategy to use to discover a binary's dynamic dependencies
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Backend {
+    /// Shell out to `ldd` and parse its stdout (the original, fragile
+    /// approach, kept around for comparison and as a fallback)
+    Ldd,
+
+    /// Parse the ELF headers ourselves, without executing anything from
+    /// the target binary
+    Elf,
+}
+
+/// Default directories searched for a library when no `RPATH`/`RUNPATH`
+/// points at it, matching the common glibc defaults for x86-64
+const DEFAULT_LIB_DIRS: &[&str] = &["/lib64", "/usr/lib64"];
+
+/// Parse `/etc/ld.so.conf` (and any `include`d files) into a list of
+/// search directories. This is a best-effort parser: it understands
+/// comments, blank lines, and `include <glob>` directives where `<glob>`
+/// is a plain path or ends in `*.conf`.
+fn parse_ld_so_conf(path: &Path) -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+
+    let Ok(contents) = std::fs::read_to_string(path) else {
+        return dirs;
+    };
+
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(pattern) = line.strip_prefix("include ") {
+            let pattern = pattern.trim();
+            if let Some(dir) = pattern.strip_suffix("/*.conf") {
+                if let Ok(entries) = std::fs::read_dir(dir) {
+                    for entry in entries.flatten() {
+                        let entry_path = entry.path();
+                        if entry_path.extension().and_then(|x| x.to_str())
+                                == Some("conf") {
+                            dirs.extend(parse_ld_so_conf(&entry_path));
+                        }
+                    }
+                }
+            } else {
+                dirs.extend(parse_ld_so_conf(Path::new(pattern)));
+            }
+            continue;
+        }
+
+        dirs.push(PathBuf::from(line));
     }
 
-    // Get the QEMU path
-    let qemu = Path::new(&args[1]);
-    if !qemu.is_file() {
-        println!("QEMU binary doesn't seem to be a valid file!");
-        return Err(Error::InvalidQemuPath);
+    dirs
+}
+
+/// Search `dirs` in order for a file named `soname`
+fn find_in_dirs(soname: &str, dirs: &[PathBuf]) -> Option<PathBuf> {
+    for dir in dirs {
+        let candidate = dir.join(soname);
+        if candidate.is_file() {
+            return Some(candidate);
+        }
     }
+    None
+}
 
-    // Get the chroot path
-    let chroot = Path::new(&args[2]);
-    if !chroot.is_dir() {
-        println!("chroot doesn't seem to be a valid directory!");
-        return Err(Error::InvalidChrootPath);
+/// Walk the transitive dynamic dependency closure of `binary` using our
+/// own ELF parser, without ever executing `binary` or `ldd`.
+///
+/// Returns the link chain for every library in the closure (not
+/// including `binary` itself) and the loader's link chain, if one was
+/// requested (shared objects such as QEMU's modules have no `PT_INTERP`
+/// of their own).
+fn elf_dependency_closure(binary: &Path)
+        -> Result<(Vec<SymlinkChain>, Option<SymlinkChain>)> {
+    let ld_so_conf_dirs = parse_ld_so_conf(Path::new("/etc/ld.so.conf"));
+
+    let mut libs: Vec<SymlinkChain> = Vec::new();
+    let mut seen: HashSet<PathBuf> = HashSet::new();
+    let mut loader = None;
+
+    let mut queue = vec![binary.to_path_buf()];
+    while let Some(current) = queue.pop() {
+        let info = elf::parse(&current)
+            .map_err(|e| Error::ElfParse(current.clone(), e))?;
+
+        if let Some(interp) = info.interp {
+            loader.get_or_insert(resolve_symlink_chain(Path::new(&interp))?);
+        }
+
+        // Search order: the binary's own RPATH/RUNPATH, then the default
+        // x86-64 library directories, then whatever `/etc/ld.so.conf`
+        // points at
+        let search_dirs: Vec<PathBuf> = info.search_dirs.iter().cloned()
+            .chain(DEFAULT_LIB_DIRS.iter().map(PathBuf::from))
+            .chain(ld_so_conf_dirs.iter().cloned())
+            .collect();
+
+        for soname in info.needed {
+            let resolved = find_in_dirs(&soname, &search_dirs)
+                .ok_or_else(|| Error::LibraryNotFound(soname.clone()))?;
+            let chain = resolve_symlink_chain(&resolved)?;
+            let canonical = chain.last().unwrap().clone();
+
+            if seen.insert(canonical.clone()) {
+                queue.push(canonical);
+            }
+            libs.push(chain);
+        }
     }
 
-    // Determine dependencies for QEMU using `ldd`
-    let ldd_res = Command::new("ldd").arg(&args[1]).output()
+    Ok((libs, loader))
+}
+
+/// Determine QEMU's dependencies by shelling out to `ldd` and parsing its
+/// stdout. This is the original implementation, kept around behind
+/// `--backend=ldd` since it's simple and matches what a human would run by
+/// hand, but it executes code from the target binary's own loader to do so.
+///
+/// Returns the link chain for every library `ldd` reported and the
+/// loader's link chain, if `ldd` reported one (shared objects such as
+/// QEMU's modules don't have one of their own).
+fn ldd_dependency_closure(qemu: &Path)
+        -> Result<(Vec<SymlinkChain>, Option<SymlinkChain>)> {
+    let ldd_res = Command::new("ldd").arg(qemu).output()
         .map_err(Error::RunLddFailed)?;
     if !ldd_res.status.success() {
         return Err(Error::LddError(ldd_res.status.code()));
@@ -112,53 +297,339 @@ fn main() -> Result<()> {
         }
     }
 
-    // Make sure we had a loader
-    if loader.is_none() {
-        println!("No dynamic loader found for binary, is the binary \
-            statically linked?");
-        return Err(Error::UnexpectedLddOutput);
+    let loader = loader.map(|x| resolve_symlink_chain(Path::new(x))).transpose()?;
+
+    let libs = libs.iter().map(|x| resolve_symlink_chain(Path::new(x)))
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok((libs, loader))
+}
+
+/// Copy `file` into `dest_dir` (relative to `chroot`), creating `dest_dir`
+/// if it doesn't already exist. `copied` tracks the destination paths
+/// already placed into the chroot so a library pulled in by multiple
+/// binaries or modules only gets copied once to a given destination (the
+/// same library can legitimately need to land in more than one place,
+/// e.g. the dynamic loader is both a regular dependency under
+/// `/lib64/x86_64` and needs to exist at its own absolute path).
+fn copy_into(
+    chroot: &Path,
+    dest_dir: &Path,
+    file: &Path,
+    copied: &mut HashSet<PathBuf>,
+) -> Result<()> {
+    let dest = chroot.join(dest_dir);
+    let dest_file = dest.join(file.file_name().unwrap());
+
+    if !copied.insert(dest_file.clone()) {
+        return Ok(());
     }
 
-    // Make sure everything we found is a file, and perform the copy
-    for (custom_path, lib) in
-            libs.iter().map(|x| (Some("lib64/x86_64"), x))
-            .chain(Some((Some("usr/bin"), &args[1].as_str())))
-            .chain(loader.iter().map(|x| (None, x))) {
-        let lib_path = Path::new(lib).canonicalize()
-            .map_err(Error::LibCanonicalize)?;
-        if !lib_path.is_file() {
-            println!("Dynamic dependency is not a valid file: {lib}");
-            return Err(Error::UnexpectedLddOutput);
+    std::fs::create_dir_all(&dest)
+        .map_err(|x| Error::CreateOutputDirectory(dest.clone(), x))?;
+
+    println!("Copying {:?} -> {:?}", file, dest_file);
+    std::fs::copy(file, &dest_file)
+        .map_err(|x| Error::CopyFile(file.to_path_buf(), dest_file, x))?;
+
+    Ok(())
+}
+
+/// Copy a resolved library's link `chain` into `dest_dir` (relative to
+/// `chroot`): the real file at `chain.last()` is copied once, and every
+/// symlink that was followed to reach it (`chain[..len - 1]`) is
+/// recreated alongside it pointing at the real file's name. This mirrors
+/// the layout a real `/lib64` has, where e.g. `libfoo.so.6` is a symlink
+/// to the fully-versioned `libfoo.so.6.2.1` that actually ships the code.
+fn copy_chain_into(
+    chroot: &Path,
+    dest_dir: &Path,
+    chain: &SymlinkChain,
+    copied: &mut HashSet<PathBuf>,
+) -> Result<()> {
+    let dest = chroot.join(dest_dir);
+    let real_file = chain.last().unwrap();
+    let real_dest = dest.join(real_file.file_name().unwrap());
+
+    if copied.insert(real_dest.clone()) {
+        std::fs::create_dir_all(&dest)
+            .map_err(|x| Error::CreateOutputDirectory(dest.clone(), x))?;
+
+        println!("Copying {:?} -> {:?}", real_file, real_dest);
+        std::fs::copy(real_file, &real_dest)
+            .map_err(|x| Error::CopyFile(real_file.clone(), real_dest.clone(), x))?;
+    }
+
+    for link in &chain[..chain.len() - 1] {
+        let link_dest = dest.join(link.file_name().unwrap());
+        if link_dest == real_dest || !copied.insert(link_dest.clone()) {
+            continue;
+        }
+
+        std::fs::create_dir_all(&dest)
+            .map_err(|x| Error::CreateOutputDirectory(dest.clone(), x))?;
+
+        let link_target = real_dest.file_name().unwrap();
+        println!("Linking {:?} -> {:?}", link_dest, link_target);
+        std::os::unix::fs::symlink(link_target, &link_dest)
+            .map_err(|x| Error::CreateSymlink(link_dest, real_dest.clone(), x))?;
+    }
+
+    Ok(())
+}
+
+/// QEMU's modules live in `<prefix>/lib/qemu`, where `<prefix>` is the
+/// directory one level above the `bin` directory the QEMU binary itself
+/// was installed into (e.g. `/usr/bin/qemu-system-x86_64` implies
+/// `/usr/lib/qemu`)
+fn default_modules_dir(qemu: &Path) -> Result<PathBuf> {
+    let qemu = qemu.canonicalize().map_err(Error::LibCanonicalize)?;
+    let bin_dir = qemu.parent().ok_or(Error::InvalidQemuPath)?;
+    let prefix = bin_dir.parent().ok_or(Error::InvalidQemuPath)?;
+    Ok(prefix.join("lib/qemu"))
+}
+
+/// Copy every `*.so` module out of `modules_dir` into the chroot at a
+/// matching absolute path, then resolve and copy each module's own
+/// transitive dependencies just like we do for the main binary. It's not
+/// an error for `modules_dir` to not exist; plenty of QEMU builds are
+/// monolithic and have no modules at all.
+fn copy_modules(
+    chroot: &Path,
+    modules_dir: &Path,
+    backend: Backend,
+    copied: &mut HashSet<PathBuf>,
+) -> Result<()> {
+    let entries = match std::fs::read_dir(modules_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(Error::ReadModulesDir(modules_dir.to_path_buf(), e)),
+    };
+
+    let modules_dir = modules_dir.canonicalize().map_err(Error::LibCanonicalize)?;
+    let dest_dir = modules_dir.strip_prefix("/").unwrap();
+
+    for entry in entries {
+        let entry = entry.map_err(|e| Error::ReadModulesDir(modules_dir.clone(), e))?;
+        let path = entry.path();
+        if path.extension().and_then(|x| x.to_str()) != Some("so") {
+            continue;
+        }
+        let path = path.canonicalize().map_err(Error::LibCanonicalize)?;
+
+        copy_into(chroot, dest_dir, &path, copied)?;
+
+        // Modules are `dlopen()`'d directly and never go through `ldd`, so
+        // run the same dependency-closure logic on them to pull their own
+        // libraries into `/lib64/x86_64`
+        let (libs, _loader) = match backend {
+            Backend::Ldd => ldd_dependency_closure(&path)?,
+            Backend::Elf => elf_dependency_closure(&path)?,
+        };
+        for lib in &libs {
+            copy_chain_into(chroot, Path::new("lib64/x86_64"), lib, copied)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Does `path` start with the ELF magic bytes? Used to filter a directory
+/// of binaries down to the ones actually worth walking, since a real QEMU
+/// install directory routinely has non-ELF files (READMEs, wrapper
+/// scripts) sitting right next to the binaries
+fn looks_like_elf(path: &Path) -> bool {
+    let mut magic = [0u8; 4];
+    let Ok(mut file) = std::fs::File::open(path) else {
+        return false;
+    };
+    std::io::Read::read_exact(&mut file, &mut magic).is_ok() && magic == *b"\x7fELF"
+}
+
+/// Entry point
+fn main() -> Result<()> {
+    // Get the arguments, pulling the optional `--backend=` flag out from
+    // wherever it appears
+    let raw_args: Vec<String> = std::env::args().collect::<Vec<_>>();
+    let mut backend = Backend::Elf;
+    let mut modules_dir: Option<PathBuf> = None;
+    let mut register_binfmt = false;
+    let mut positional = Vec::new();
+    for arg in raw_args.iter().skip(1) {
+        if let Some(value) = arg.strip_prefix("--backend=") {
+            backend = match value {
+                "ldd" => Backend::Ldd,
+                "elf" => Backend::Elf,
+                _ => {
+                    println!("Unknown backend {value:?}, expected \
+                        `ldd` or `elf`");
+                    return Err(Error::InvalidArgs);
+                }
+            };
+        } else if let Some(value) = arg.strip_prefix("--modules-dir=") {
+            modules_dir = Some(PathBuf::from(value));
+        } else if arg == "--register-binfmt" {
+            register_binfmt = true;
+        } else {
+            positional.push(arg.clone());
         }
+    }
+
+    if positional.len() < 2 {
+        println!("usage: qemu_chrooter [--backend=ldd|elf] \
+            [--modules-dir=<path>] [--register-binfmt] \
+            <path to QEMU binary or directory of binaries>... \
+            <path to chroot>");
+        return Err(Error::InvalidArgs);
+    }
+
+    // The last positional argument is always the chroot, everything
+    // before it names a binary to install, or a directory of them
+    let (chroot_arg, input_args) = positional.split_last().unwrap();
+
+    // Get the chroot path
+    let chroot = Path::new(chroot_arg);
+    if !chroot.is_dir() {
+        println!("chroot doesn't seem to be a valid directory!");
+        return Err(Error::InvalidChrootPath);
+    }
 
-        // Get the folder where the library is contained
-        let target_dir = if let Some(custom_path) = custom_path {
-            Path::new(custom_path)
+    // Expand every input into a flat list of binaries. A real deployment
+    // installs `qemu-system-x86_64`, `qemu-x86_64`, `qemu-img`, etc. all
+    // side by side, so letting a single directory stand in for all of
+    // them saves having to invoke this tool once per binary
+    let mut binaries = Vec::new();
+    for input in input_args {
+        let path = Path::new(input);
+        if path.is_dir() {
+            for entry in std::fs::read_dir(path)
+                    .map_err(|_| Error::InvalidQemuPath)? {
+                let entry_path = entry.map_err(|_| Error::InvalidQemuPath)?.path();
+                // A real install directory mixes binaries in with
+                // READMEs, wrapper scripts, etc.; only the former are
+                // worth walking
+                if entry_path.is_file() && looks_like_elf(&entry_path) {
+                    binaries.push(entry_path);
+                }
+            }
+        } else if path.is_file() {
+            binaries.push(path.to_path_buf());
         } else {
-            // Loader must be put in the _exact_ path specified in the chroot
-            // perspective, so we don't put it in `/lib64/x86_64` as we put the
-            // others
-            let parent = lib_path.parent().unwrap();
-            parent.strip_prefix("/").unwrap()
+            println!("{input} doesn't seem to be a valid file or \
+                directory!");
+            return Err(Error::InvalidQemuPath);
+        }
+    }
+
+    if binaries.is_empty() {
+        println!("No binaries found to install!");
+        return Err(Error::InvalidArgs);
+    }
+
+    // Libraries already copied into the chroot, by destination path, so a
+    // dependency shared by multiple binaries (glibc, say) is only ever
+    // copied once
+    let mut copied: HashSet<PathBuf> = HashSet::new();
+    let mut layouts = Vec::new();
+
+    for qemu in &binaries {
+        // Determine dependencies using the selected backend. A single bad
+        // entry (a non-ELF file that slipped through, or a perfectly
+        // normal statically-linked binary with no loader to find) is not
+        // reason enough to abort a batch install of otherwise-good
+        // binaries, so these two cases are reported and skipped rather
+        // than propagated with `?`.
+        let deps = match backend {
+            Backend::Ldd => ldd_dependency_closure(qemu),
+            Backend::Elf => elf_dependency_closure(qemu),
+        }.and_then(|(libs, loader)| {
+            let loader = loader.ok_or_else(|| Error::NoLoaderFound(qemu.clone()))?;
+            Ok((libs, loader))
+        });
+        let (libs, loader) = match deps {
+            Ok(deps) => deps,
+            Err(Error::ElfParse(path, e)) => {
+                println!("Skipping {path:?}: doesn't look like a valid \
+                    ELF binary ({e:?})");
+                continue;
+            }
+            Err(Error::NoLoaderFound(path)) => {
+                println!("Skipping {path:?}: no dynamic loader found, \
+                    probably statically linked");
+                continue;
+            }
+            // The `ldd` backend has its own ways to fail on a single bad
+            // binary (not executable, not dynamically linked, `ldd`
+            // itself choking on it); these are just as much a "skip this
+            // one binary" case as the ELF-backend failures above
+            Err(e @ (Error::RunLddFailed(_) | Error::LddError(_)
+                    | Error::LddInvalidUtf8(_)
+                    | Error::UnexpectedLddOutput)) => {
+                println!("Skipping {qemu:?}: ldd backend failed ({e:?})");
+                continue;
+            }
+            Err(e) => return Err(e),
         };
 
-        // Determine directory where we will be placing the file
-        let dest = chroot.join(target_dir);
+        // Every destination below is derived from this one layout, so the
+        // copier and the wrapper script it's paired with can never
+        // disagree. The loader is placed using the path it was originally
+        // referenced by (`loader[0]`), since that's the exact string
+        // baked into every binary's `PT_INTERP`.
+        let layout = match launch::Layout::new(qemu, &loader[0]) {
+            Ok(layout) => layout,
+            Err(e @ launch::Error::InvalidLoaderPath(_)) => {
+                println!("Skipping {qemu:?}: {e:?}");
+                continue;
+            }
+            Err(e) => return Err(Error::Launch(e)),
+        };
 
-        // Make sure the target path exists
-        std::fs::create_dir_all(&dest)
-            .map_err(|x| Error::CreateOutputDirectory(dest.clone(), x))?;
+        // Copy every dependency into `/lib64/x86_64`, reproducing any
+        // SONAME symlink chain rather than flattening it to one file
+        for lib in &libs {
+            copy_chain_into(chroot, &layout.lib_dir, lib, &mut copied)?;
+        }
+
+        // Copy the binary itself into `/usr/bin`
+        copy_into(chroot, &layout.bin_dir, qemu, &mut copied)?;
+
+        // Loader must be put in the _exact_ path specified in the chroot
+        // perspective, so we don't put it in `/lib64/x86_64` as we put the
+        // others
+        copy_chain_into(chroot, &layout.loader_dir, &loader, &mut copied)?;
 
-        // Copy file
-        let dest_file = dest.join(Path::new(lib).file_name().unwrap());
-        println!("Copying {:?} -> {:?}", lib_path, dest_file);
-        std::fs::copy(&lib_path, &dest_file)
-            .map_err(|x| {
-                Error::CopyFile(lib_path.clone(), dest_file.clone(), x)
-            })?;
+        layouts.push(layout);
     }
 
-    // Copy QEMU itself
+    // QEMU frequently `dlopen()`s block/accel/audio/net backends out of
+    // its module directory, which `ldd`/ELF dependency walking of the
+    // main binary alone will never see. All the binaries we were given
+    // are expected to share one installation prefix, so one modules
+    // directory covers all of them.
+    let modules_dir = match modules_dir {
+        Some(dir) => dir,
+        None => default_modules_dir(&binaries[0])?,
+    };
+    copy_modules(chroot, &modules_dir, backend, &mut copied)?;
+
+    // The wrapper script and binfmt_misc registration only make sense for
+    // a single emulator, since they need to pick one binary to invoke
+    if let [layout] = layouts.as_slice() {
+        // Generate a wrapper that invokes the copied QEMU with the right
+        // loader and library path, so callers don't need to know anything
+        // about the chroot's internal layout
+        launch::write_wrapper(chroot, layout).map_err(Error::Launch)?;
+
+        if register_binfmt {
+            let wrapper_host_path = chroot.join(launch::wrapper_path());
+            launch::register_binfmt(&layout.qemu_name, &wrapper_host_path)
+                .map_err(Error::Launch)?;
+        }
+    } else if register_binfmt {
+        println!("--register-binfmt only applies when installing a \
+            single binary, skipping");
+    }
 
     Ok(())
 }